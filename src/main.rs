@@ -1,12 +1,15 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 use clap::{Parser, Subcommand};
 use fst::automaton::Str;
 use fst::{Automaton, IntoStreamer, Streamer};
-use levenshtein_automata::LevenshteinAutomatonBuilder;
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA};
 use memmap2::Mmap;
+use roaring::RoaringBitmap;
 
 const POSSIBLE_TYPOS: &[&str] = &["0", "1", "2"];
 
@@ -38,6 +41,23 @@ enum Command {
         prefix: String,
         #[clap(long, possible_values = POSSIBLE_TYPOS)]
         typos: u8,
+        /// Load a `build-dfa-cache`-produced transition table from this path
+        /// and memory-map it instead of calling `build_prefix_dfa` again.
+        #[clap(long)]
+        dfa_cache: Option<PathBuf>,
+    },
+
+    /// Builds the prefix Levenshtein DFA for `prefix`/`typos`, serializes its
+    /// dense transition table to `output`, and exits. A later `--dfa-cache
+    /// <output>` run can then memory-map the file instead of paying
+    /// `build_prefix_dfa`'s construction cost again.
+    BuildDfaCache {
+        #[clap(long)]
+        prefix: String,
+        #[clap(long, possible_values = POSSIBLE_TYPOS)]
+        typos: u8,
+        #[clap(long)]
+        output: PathBuf,
     },
 
     /// Uses a new technique to iterate over the subset of words that starts
@@ -49,6 +69,54 @@ enum Command {
         typos: u8,
         #[clap(long)]
         no_swap: bool,
+        /// Instead of only counting matches, recover the edit distance of
+        /// each one and print them sorted by distance (then lexicographically),
+        /// the same way `word_derivations` ranks results in milli. Only takes
+        /// effect with `--typos 2` (and, with `--lazy-escalation`, only when
+        /// the search escalates); otherwise it is ignored.
+        #[clap(long)]
+        ranked: bool,
+        /// Number of leading characters of `prefix` considered "free" when
+        /// searching for the typos==2 branch that tolerates a typo among
+        /// them, instead of hard-coding that budget to a single character.
+        #[clap(long, default_value_t = 1)]
+        free_prefix_chars: usize,
+        /// For typos==2, only build and stream the expensive two-typo DFA
+        /// branches if the cheaper one-typo search returns fewer than
+        /// `min_results` words, mirroring milli's `compute_fully_if_needed`.
+        #[clap(long)]
+        lazy_escalation: bool,
+        /// Result-count threshold below which `--lazy-escalation` escalates
+        /// to the two-typo DFA.
+        #[clap(long, default_value_t = 10)]
+        min_results: usize,
+        /// Instead of only listing matched words, print how many of their
+        /// leading characters actually correspond to the matched query term
+        /// (the shortest prefix already accepted by the typo DFA), the same
+        /// way milli's `MatchingWords::match_token` reports a match span for
+        /// highlighting. Ignored whenever `--ranked` output is printed.
+        #[clap(long)]
+        with_bounds: bool,
+    },
+
+    /// Runs the same typo-tolerant prefix expansion as `BetterPrefixDFA`, then
+    /// looks each matched word up in a postings store and unions the
+    /// document ids it maps to, the way the words-FST drives lookups into
+    /// the `postings-ids` database in MeiliSearch.
+    SearchDocuments {
+        #[clap(long)]
+        prefix: String,
+        #[clap(long, possible_values = POSSIBLE_TYPOS)]
+        typos: u8,
+        #[clap(long)]
+        no_swap: bool,
+        /// Path to a flat key -> RoaringBitmap postings store mapping words
+        /// to the document ids that contain them.
+        #[clap(long)]
+        postings_db: PathBuf,
+        /// See `BetterPrefixDFA --free-prefix-chars`.
+        #[clap(long, default_value_t = 1)]
+        free_prefix_chars: usize,
     },
 }
 
@@ -59,7 +127,7 @@ fn main() -> anyhow::Result<()> {
     let fst_mmap = unsafe { Mmap::map(&fst_file)? };
     let fst = fst::Set::new(fst_mmap)?;
 
-    let mut count = 0;
+    let mut count: usize = 0;
     let before = match opt.command {
         Command::AllSimple => {
             let before = Instant::now();
@@ -78,133 +146,127 @@ fn main() -> anyhow::Result<()> {
             }
             before
         }
-        Command::CurrentPrefixDFA { prefix, typos } => {
-            let dfa_builder = LevenshteinAutomatonBuilder::new(typos, true);
+        Command::CurrentPrefixDFA { prefix, typos, dfa_cache } => {
             let first_char = split_first_char(&prefix).0;
 
-            let before = Instant::now();
-            let dfa = dfa_builder.build_prefix_dfa(&prefix);
-            eprintln!("dfa creation took {:.02?}", before.elapsed());
-            let builder = fst.search_with_state(&dfa);
-            let mut iter = builder.into_stream();
-            while let Some((word, state)) = iter.next() {
-                let word = unsafe { std::str::from_utf8_unchecked(word) };
-                let curr_first_char = split_first_char(word).0;
-                if typos == 0 {
-                    count += 1;
-                } else if typos == 1 && curr_first_char == first_char {
-                    count += 1;
-                } else if typos == 2 {
-                    // We consider 1 typo on the first char as 2 typos, so we either accept:
-                    // - 2 typos in the tail of the words or,
-                    // - 1 typo on the first char
-                    if curr_first_char == first_char {
-                        count += 1;
-                    } else if dfa.distance(state).to_u8() < 2 {
-                        count += 1;
-                    }
-                }
-            }
-            before
-        }
-        Command::BetterPrefixDFA { prefix, typos, no_swap } => {
-            if typos == 1 {
-                let dfa_builder = LevenshteinAutomatonBuilder::new(1, true);
-                let first_char = split_first_char(&prefix).0;
+            if let Some(dfa_cache) = dfa_cache {
+                let before = Instant::now();
+                let cache_file = File::open(&dfa_cache)?;
+                let cache_mmap = unsafe { Mmap::map(&cache_file)? };
+                let dfa = DenseDfaView::from_bytes(&cache_mmap)?;
+                eprintln!("dfa cache mapping took {:.02?}", before.elapsed());
+
+                count = count_current_prefix_dfa(&fst, &dfa, typos, first_char, |s| dfa.distance(s));
+                before
+            } else {
+                let dfa_builder = LevenshteinAutomatonBuilder::new(typos, true);
 
                 let before = Instant::now();
                 let dfa = dfa_builder.build_prefix_dfa(&prefix);
                 eprintln!("dfa creation took {:.02?}", before.elapsed());
 
-                let starts = Str::new(first_char).starts_with();
-                let builder = fst.search(starts.intersection(dfa));
+                count = count_current_prefix_dfa(&fst, &dfa, typos, first_char, |s| dfa.distance(s));
+                before
+            }
+        }
+        Command::BuildDfaCache { prefix, typos, output } => {
+            let before = Instant::now();
+            let dfa = LevenshteinAutomatonBuilder::new(typos, true).build_prefix_dfa(&prefix);
+            eprintln!("dfa creation took {:.02?}", before.elapsed());
 
-                let mut iter = builder.into_stream();
-                while let Some(_word) = iter.next() {
-                    count += 1;
-                }
+            let bytes = dense_dfa_to_bytes(&dfa);
+            std::fs::write(&output, &bytes)?;
+            eprintln!("wrote {} states ({} bytes) to {}", dfa.num_states(), bytes.len(), output.display());
 
-                before
-            } else if typos == 2 {
-                if no_swap {
-                    let dfa_two_typos_builder = LevenshteinAutomatonBuilder::new(2, true);
-                    let (first_char, tail) = split_first_char(&prefix);
-
-                    let before = Instant::now();
-                    let any_first_char_exact_tail = AnyFirstByteStr::new(tail).starts_with();
-                    let two_typos_dfa = dfa_two_typos_builder.build_prefix_dfa(&prefix);
-                    eprintln!("dfa creation took {:.02?}", before.elapsed());
-
-                    // The first char is a typo, we search the intersect between that and
-                    // what the one-typo DFA can find. Since we use damereau (swap = 1 typo)
-                    // we can't optimize that further and must use this damereau levenshtein DFA.
-                    let starts_with_typo = Str::new(first_char).starts_with().complement();
-                    let first_typo_and_tail_one_typo =
-                        starts_with_typo.intersection(any_first_char_exact_tail);
-
-                    // The first char is valid, this is a small subset, we search two typos
-                    // on the tail of the word (everything but the first char) with a two typo DFA.
-                    let starts_with_first_char = Str::new(first_char).starts_with();
-                    let tail_two_typos = starts_with_first_char.intersection(two_typos_dfa);
-
-                    // We want to find the union of:
-                    // - 1 typo on the first char (considered 2 by us) followed by 0 typos in the tail,
-                    // - 0 typo on the first char followed by 2 typos in the tail.
-                    let two_typos = first_typo_and_tail_one_typo.union(tail_two_typos);
-
-                    let builder = fst.search(two_typos);
-                    let mut iter = builder.into_stream();
-                    while let Some(_word) = iter.next() {
-                        count += 1;
+            count = dfa.num_states();
+            before
+        }
+        Command::BetterPrefixDFA {
+            prefix,
+            typos,
+            no_swap,
+            ranked,
+            free_prefix_chars,
+            lazy_escalation,
+            min_results,
+            with_bounds,
+        } => {
+            if typos == 2 && lazy_escalation {
+                let before = Instant::now();
+                let one_typo_words = expand_typo_prefix(&fst, &prefix, 1, no_swap, free_prefix_chars)?;
+                let one_typo_count = one_typo_words.len();
+                let escalate = one_typo_count < min_results;
+                eprintln!(
+                    "one-typo search found {} result(s) (min-results={}), {}",
+                    one_typo_count,
+                    min_results,
+                    if escalate { "escalating to two-typo DFA" } else { "skipping two-typo DFA" }
+                );
+
+                if escalate {
+                    if ranked {
+                        let ranked_words =
+                            rank_typo_prefix(&fst, &prefix, no_swap, free_prefix_chars);
+                        count = ranked_words.len();
+                        for (word, distance) in &ranked_words {
+                            println!("{}\t{}", word, distance);
+                        }
+                    } else {
+                        let words = expand_typo_prefix(&fst, &prefix, 2, no_swap, free_prefix_chars)?;
+                        count = words.len();
+                        if with_bounds {
+                            print_with_bounds(&prefix, 2, &words);
+                        }
                     }
-
-                    before
                 } else {
-                    let dfa_one_typo_builder = LevenshteinAutomatonBuilder::new(1, true);
-                    let dfa_two_typos_builder = LevenshteinAutomatonBuilder::new(2, true);
-                    let first_char = split_first_char(&prefix).0;
-
-                    let before = Instant::now();
-                    let one_typo_dfa = dfa_one_typo_builder.build_prefix_dfa(&prefix);
-                    let two_typos_dfa = dfa_two_typos_builder.build_prefix_dfa(&prefix);
-                    eprintln!("dfa creation took {:.02?}", before.elapsed());
-
-                    // The first char is a typo, we search the intersect between that and
-                    // what the one-typo DFA can find. Since we use damereau (swap = 1 typo)
-                    // we can't optimize that further and must use this damereau levenshtein DFA.
-                    let starts_with_typo = Str::new(first_char).starts_with().complement();
-                    let first_typo_and_tail_one_typo = starts_with_typo.intersection(one_typo_dfa);
-
-                    // The first char is valid, this is a small subset, we search two typos
-                    // on the tail of the word (everything but the first char) with a two typo DFA.
-                    let starts_with_first_char = Str::new(first_char).starts_with();
-                    let tail_two_typos = starts_with_first_char.intersection(two_typos_dfa);
-
-                    // We want to find the union of:
-                    // - 1 typo on the first char (considered 2 by us) followed by 0 typos in the tail,
-                    // - 0 typo on the first char followed by 2 typos in the tail.
-                    let two_typos = first_typo_and_tail_one_typo.union(tail_two_typos);
-
-                    let builder = fst.search(two_typos);
-                    let mut iter = builder.into_stream();
-                    while let Some(_word) = iter.next() {
-                        count += 1;
+                    count = one_typo_count;
+                    if with_bounds {
+                        print_with_bounds(&prefix, 1, &one_typo_words);
                     }
+                }
 
-                    before
+                before
+            } else if ranked && typos == 2 {
+                let before = Instant::now();
+                let ranked_words = rank_typo_prefix(&fst, &prefix, no_swap, free_prefix_chars);
+                count = ranked_words.len();
+                for (word, distance) in &ranked_words {
+                    println!("{}\t{}", word, distance);
                 }
+                before
             } else {
                 let before = Instant::now();
-                let builder = fst.search(Str::new(&prefix).starts_with());
-
-                let mut iter = builder.into_stream();
-                while let Some(_word) = iter.next() {
-                    count += 1;
+                let words = expand_typo_prefix(&fst, &prefix, typos, no_swap, free_prefix_chars)?;
+                count = words.len();
+                if with_bounds {
+                    print_with_bounds(&prefix, typos, &words);
                 }
-
                 before
             }
         }
+        Command::SearchDocuments { prefix, typos, no_swap, postings_db, free_prefix_chars } => {
+            let before = Instant::now();
+            let words = expand_typo_prefix(&fst, &prefix, typos, no_swap, free_prefix_chars)?;
+            let postings = load_postings_db(&postings_db)?;
+
+            let mut matched_words = 0;
+            let mut document_ids = RoaringBitmap::new();
+            for word in &words {
+                if let Some(bitmap) = postings.get(word) {
+                    matched_words += 1;
+                    document_ids |= bitmap;
+                }
+            }
+
+            eprintln!(
+                "{} distinct words matched, {} documents in total.",
+                matched_words,
+                document_ids.len()
+            );
+
+            count = document_ids.len() as usize;
+            before
+        }
     };
 
     eprintln!("Took {:.02?} to output {} values.", before.elapsed(), count);
@@ -217,55 +279,526 @@ fn split_first_char(s: &str) -> (&str, &str) {
     s.split_at(c.len_utf8())
 }
 
+/// Shared `CurrentPrefixDFA` counting loop, generic over the automaton used
+/// to search (a freshly built `DFA` or a memory-mapped `DenseDfaView`) so
+/// `--dfa-cache` doesn't need its own copy of this logic.
+fn count_current_prefix_dfa<A>(
+    fst: &fst::Set<Mmap>,
+    automaton: &A,
+    typos: u8,
+    first_char: &str,
+    distance: impl Fn(u32) -> Distance,
+) -> usize
+where
+    A: Automaton<State = u32>,
+{
+    let mut count = 0;
+    let builder = fst.search_with_state(automaton);
+    let mut iter = builder.into_stream();
+    while let Some((word, state)) = iter.next() {
+        let word = unsafe { std::str::from_utf8_unchecked(word) };
+        let curr_first_char = split_first_char(word).0;
+        if typos == 0 {
+            count += 1;
+        } else if typos == 1 && curr_first_char == first_char {
+            count += 1;
+        } else if typos == 2 {
+            // We consider 1 typo on the first char as 2 typos, so we either accept:
+            // - 2 typos in the tail of the words or,
+            // - 1 typo on the first char
+            if curr_first_char == first_char {
+                count += 1;
+            } else if distance(state).to_u8() < 2 {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Splits `s` after its first `chars` characters, clamping to the string's
+/// own length when it has fewer.
+fn split_prefix_chars(s: &str, chars: usize) -> (&str, &str) {
+    let split_at = s.char_indices().nth(chars).map_or(s.len(), |(idx, _)| idx);
+    s.split_at(split_at)
+}
+
+/// Runs the typos==2 `BetterPrefixDFA --ranked` search and returns the
+/// `(word, distance)` pairs sorted by distance, factored out so both the
+/// plain and `--lazy-escalation` code paths can share it.
+fn rank_typo_prefix(
+    fst: &fst::Set<Mmap>,
+    prefix: &str,
+    no_swap: bool,
+    free_prefix_chars: usize,
+) -> Vec<(String, u8)> {
+    let (prefix_k, tail) = split_prefix_chars(prefix, free_prefix_chars);
+
+    if no_swap {
+        let dfa_two_typos_builder = LevenshteinAutomatonBuilder::new(2, true);
+
+        let before = Instant::now();
+        let any_prefix_exact_tail = SkipPrefixStr::new(free_prefix_chars, tail).starts_with();
+        let two_typos_dfa = dfa_two_typos_builder.build_prefix_dfa(prefix);
+        eprintln!("dfa creation took {:.02?}", before.elapsed());
+
+        // The free prefix is a typo, we search the intersect between that and
+        // what the tail-exact automaton can find: any free prefix followed by
+        // the exact tail, with no further typo allowed in the tail.
+        let starts_with_typo = Str::new(prefix_k).starts_with().complement();
+        let first_typo_and_tail_one_typo = starts_with_typo.intersection(any_prefix_exact_tail);
+
+        rank_two_typo_branches(fst, prefix_k, first_typo_and_tail_one_typo, &two_typos_dfa)
+    } else {
+        let dfa_one_typo_builder = LevenshteinAutomatonBuilder::new(1, true);
+        let dfa_two_typos_builder = LevenshteinAutomatonBuilder::new(2, true);
+
+        let before = Instant::now();
+        let one_typo_dfa = dfa_one_typo_builder.build_prefix_dfa(prefix);
+        let two_typos_dfa = dfa_two_typos_builder.build_prefix_dfa(prefix);
+        eprintln!("dfa creation took {:.02?}", before.elapsed());
+
+        // The free prefix is a typo, we search the intersect between that and
+        // what the one-typo DFA can find. Since we use damereau (swap = 1 typo)
+        // we can't optimize that further and must use this damereau levenshtein DFA.
+        let starts_with_typo = Str::new(prefix_k).starts_with().complement();
+        let first_typo_and_tail_one_typo = starts_with_typo.intersection(one_typo_dfa);
+
+        rank_two_typo_branches(fst, prefix_k, first_typo_and_tail_one_typo, &two_typos_dfa)
+    }
+}
+
+/// Runs the `BetterPrefixDFA` typo-tolerant prefix expansion and returns
+/// every matching word, so other commands (like `SearchDocuments`) can reuse
+/// the same typo logic instead of duplicating it.
+fn expand_typo_prefix(
+    fst: &fst::Set<Mmap>,
+    prefix: &str,
+    typos: u8,
+    no_swap: bool,
+    free_prefix_chars: usize,
+) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+
+    if typos == 0 {
+        let builder = fst.search(Str::new(prefix).starts_with());
+        let mut iter = builder.into_stream();
+        while let Some(word) = iter.next() {
+            words.push(String::from_utf8(word.to_vec())?);
+        }
+    } else if typos == 1 {
+        let dfa_builder = LevenshteinAutomatonBuilder::new(1, true);
+        let first_char = split_first_char(prefix).0;
+
+        let before = Instant::now();
+        let dfa = dfa_builder.build_prefix_dfa(prefix);
+        eprintln!("dfa creation took {:.02?}", before.elapsed());
+
+        let starts = Str::new(first_char).starts_with();
+        let builder = fst.search(starts.intersection(dfa));
+        let mut iter = builder.into_stream();
+        while let Some(word) = iter.next() {
+            words.push(String::from_utf8(word.to_vec())?);
+        }
+    } else if no_swap {
+        let dfa_two_typos_builder = LevenshteinAutomatonBuilder::new(2, true);
+        let (prefix_k, tail) = split_prefix_chars(prefix, free_prefix_chars);
+
+        let before = Instant::now();
+        let any_prefix_exact_tail = SkipPrefixStr::new(free_prefix_chars, tail).starts_with();
+        let two_typos_dfa = dfa_two_typos_builder.build_prefix_dfa(prefix);
+        eprintln!("dfa creation took {:.02?}", before.elapsed());
+
+        let starts_with_typo = Str::new(prefix_k).starts_with().complement();
+        let first_typo_and_tail_one_typo = starts_with_typo.intersection(any_prefix_exact_tail);
+
+        let starts_with_prefix_k = Str::new(prefix_k).starts_with();
+        let tail_two_typos = starts_with_prefix_k.intersection(two_typos_dfa);
+
+        let two_typos = first_typo_and_tail_one_typo.union(tail_two_typos);
+        let builder = fst.search(two_typos);
+        let mut iter = builder.into_stream();
+        while let Some(word) = iter.next() {
+            words.push(String::from_utf8(word.to_vec())?);
+        }
+    } else {
+        let dfa_one_typo_builder = LevenshteinAutomatonBuilder::new(1, true);
+        let dfa_two_typos_builder = LevenshteinAutomatonBuilder::new(2, true);
+        let prefix_k = split_prefix_chars(prefix, free_prefix_chars).0;
+
+        let before = Instant::now();
+        let one_typo_dfa = dfa_one_typo_builder.build_prefix_dfa(prefix);
+        let two_typos_dfa = dfa_two_typos_builder.build_prefix_dfa(prefix);
+        eprintln!("dfa creation took {:.02?}", before.elapsed());
+
+        let starts_with_typo = Str::new(prefix_k).starts_with().complement();
+        let first_typo_and_tail_one_typo = starts_with_typo.intersection(one_typo_dfa);
+
+        let starts_with_prefix_k = Str::new(prefix_k).starts_with();
+        let tail_two_typos = starts_with_prefix_k.intersection(two_typos_dfa);
+
+        let two_typos = first_typo_and_tail_one_typo.union(tail_two_typos);
+        let builder = fst.search(two_typos);
+        let mut iter = builder.into_stream();
+        while let Some(word) = iter.next() {
+            words.push(String::from_utf8(word.to_vec())?);
+        }
+    }
+
+    Ok(words)
+}
+
+/// Searches the two composite typos==2 branches (free-prefix-typo'd tail
+/// match, and exact-prefix two-typo tail match), recovering the real edit
+/// distance for each matched word and keeping the minimum when a word is
+/// found by both branches.
+fn rank_two_typo_branches<A: Automaton>(
+    fst: &fst::Set<Mmap>,
+    prefix_k: &str,
+    first_typo_branch: A,
+    two_typos_dfa: &DFA,
+) -> Vec<(String, u8)> {
+    let mut ranked = HashMap::new();
+
+    // The free leading chars are a typo, so this branch's effective distance
+    // is fixed at 2; we only need to know which words it matches.
+    let builder = fst.search(first_typo_branch);
+    let mut iter = builder.into_stream();
+    while let Some(word) = iter.next() {
+        let word = unsafe { std::str::from_utf8_unchecked(word) };
+        insert_min_distance(&mut ranked, word, 2);
+    }
+
+    // The leading chars are valid, recover the real distance from the
+    // two-typo DFA's own state, which we can query directly since we search with it.
+    let builder = fst.search_with_state(two_typos_dfa);
+    let mut iter = builder.into_stream();
+    while let Some((word, state)) = iter.next() {
+        let word = unsafe { std::str::from_utf8_unchecked(word) };
+        if word.starts_with(prefix_k) {
+            let distance = two_typos_dfa.distance(state).to_u8();
+            insert_min_distance(&mut ranked, word, distance);
+        }
+    }
+
+    sorted_ranked_words(ranked)
+}
+
+/// Loads a flat key -> `RoaringBitmap` postings store from disk: a sequence
+/// of records made of a little-endian `u32` key length, the key bytes, a
+/// little-endian `u32` bitmap length, and the bitmap's native roaring-rs
+/// serialization, repeated until EOF. This mirrors the words-FST ->
+/// postings-ids lookup MeiliSearch performs at query time, without pulling
+/// in a full LMDB environment for this benchmarking tool.
+fn load_postings_db(path: &Path) -> anyhow::Result<HashMap<String, RoaringBitmap>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut postings = HashMap::new();
+
+    let mut len_buf = [0u8; 4];
+    loop {
+        let read = reader.read(&mut len_buf)?;
+        if read == 0 {
+            break;
+        }
+        reader.read_exact(&mut len_buf[read..])?;
+        let key_len = u32::from_le_bytes(len_buf) as usize;
+        let mut key_buf = vec![0u8; key_len];
+        reader.read_exact(&mut key_buf)?;
+        let word = String::from_utf8(key_buf)?;
+
+        reader.read_exact(&mut len_buf)?;
+        let bitmap_len = u32::from_le_bytes(len_buf) as usize;
+        let mut bitmap_buf = vec![0u8; bitmap_len];
+        reader.read_exact(&mut bitmap_buf)?;
+        let bitmap = RoaringBitmap::deserialize_from(&bitmap_buf[..])?;
+
+        postings.insert(word, bitmap);
+    }
+
+    Ok(postings)
+}
+
+/// Inserts `word` into `ranked` with `distance`, keeping the smallest
+/// distance seen so far when the word was already found by another branch.
+fn insert_min_distance(ranked: &mut HashMap<String, u8>, word: &str, distance: u8) {
+    ranked
+        .entry(word.to_owned())
+        .and_modify(|d| *d = (*d).min(distance))
+        .or_insert(distance);
+}
+
+/// Collects the ranked words into a `Vec` sorted by distance ascending,
+/// then lexicographically, matching `word_derivations`'s ranking in milli.
+fn sorted_ranked_words(ranked: HashMap<String, u8>) -> Vec<(String, u8)> {
+    let mut ranked: Vec<(String, u8)> = ranked.into_iter().collect();
+    ranked.sort_unstable_by(|(a_word, a_dist), (b_word, b_dist)| {
+        a_dist.cmp(b_dist).then_with(|| a_word.cmp(b_word))
+    });
+    ranked
+}
+
+/// Feeds `word` into `dfa` one code point at a time and returns the number
+/// of leading characters consumed by the time the DFA first reports a
+/// match, i.e. the shortest prefix of `word` that the query term's typo
+/// budget already accounts for. Falls back to the full character count if
+/// the DFA never reports a match (e.g. it rejects the byte sequence outright).
+fn matched_char_len(dfa: &DFA, word: &str) -> usize {
+    let mut state = dfa.start();
+    for (chars_consumed, ch) in word.chars().enumerate() {
+        let mut buf = [0; 4];
+        for byte in ch.encode_utf8(&mut buf).bytes() {
+            state = dfa.accept(&state, byte);
+        }
+        if dfa.is_match(&state) {
+            return chars_consumed + 1;
+        }
+        if !dfa.can_match(&state) {
+            break;
+        }
+    }
+    word.chars().count()
+}
+
+/// Prints each word alongside `matched_char_len`, rebuilding a single
+/// `typos`-budget DFA to measure the bounds against (typos==0 has no DFA,
+/// since an exact prefix match's bound is just the prefix itself).
+fn print_with_bounds(prefix: &str, typos: u8, words: &[String]) {
+    let bound_dfa = (typos > 0).then(|| LevenshteinAutomatonBuilder::new(typos, true).build_prefix_dfa(prefix));
+    let prefix_chars = prefix.chars().count();
+
+    for word in words {
+        let matched_char_len = match &bound_dfa {
+            Some(dfa) => matched_char_len(dfa, word),
+            None => prefix_chars.min(word.chars().count()),
+        };
+        println!("{}\t{}", word, matched_char_len);
+    }
+}
+
+/// Magic bytes identifying a `dense_dfa_to_bytes`-produced cache file.
+const DENSE_DFA_MAGIC: &[u8; 4] = b"LDFA";
+/// Format version, bumped whenever the layout below changes.
+const DENSE_DFA_VERSION: u32 = 1;
+/// Header length in bytes: magic, version, state count, initial state.
+const DENSE_DFA_HEADER_LEN: usize = 4 + 4 + 4 + 4;
+
+/// Serializes `dfa`'s dense transition table and per-state distances to a
+/// flat binary format, so it can later be memory-mapped and read back
+/// zero-copy by `DenseDfaView` instead of rebuilding the DFA at query time.
+///
+/// Layout: magic (4 bytes) | version (u32 LE) | num_states (u32 LE) |
+/// initial_state (u32 LE) | num_states * 256 transitions (u32 LE each) |
+/// num_states distances (1 tag byte + 1 value byte each, tag 0 = `Exact`,
+/// tag 1 = `AtLeast`).
+fn dense_dfa_to_bytes(dfa: &DFA) -> Vec<u8> {
+    let num_states = dfa.num_states();
+    let mut bytes =
+        Vec::with_capacity(DENSE_DFA_HEADER_LEN + num_states * 256 * 4 + num_states * 2);
+
+    bytes.extend_from_slice(DENSE_DFA_MAGIC);
+    bytes.extend_from_slice(&DENSE_DFA_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(num_states as u32).to_le_bytes());
+    bytes.extend_from_slice(&dfa.initial_state().to_le_bytes());
+
+    for state in 0..num_states as u32 {
+        for byte in 0..=u8::MAX {
+            bytes.extend_from_slice(&dfa.transition(state, byte).to_le_bytes());
+        }
+    }
+    for state in 0..num_states as u32 {
+        let (tag, value) = match dfa.distance(state) {
+            Distance::Exact(d) => (0u8, d),
+            Distance::AtLeast(d) => (1u8, d),
+        };
+        bytes.push(tag);
+        bytes.push(value);
+    }
+
+    bytes
+}
+
+/// Zero-copy view over a `dense_dfa_to_bytes`-encoded byte slice (typically
+/// a memory map): reads transitions and distances directly out of the
+/// backing bytes instead of deserializing them into an owned table, and
+/// implements `Automaton` so it plugs straight into `fst::Set::search`.
+pub struct DenseDfaView<'a> {
+    bytes: &'a [u8],
+    num_states: u32,
+    initial_state: u32,
+}
+
+impl<'a> DenseDfaView<'a> {
+    pub fn from_bytes(bytes: &'a [u8]) -> anyhow::Result<DenseDfaView<'a>> {
+        anyhow::ensure!(bytes.len() >= DENSE_DFA_HEADER_LEN, "dfa cache file is truncated");
+        anyhow::ensure!(bytes[0..4] == *DENSE_DFA_MAGIC, "not a dfa cache file");
+
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        anyhow::ensure!(version == DENSE_DFA_VERSION, "unsupported dfa cache version {}", version);
+
+        let num_states = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        let initial_state = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+
+        let expected_len =
+            DENSE_DFA_HEADER_LEN + num_states as usize * 256 * 4 + num_states as usize * 2;
+        anyhow::ensure!(
+            bytes.len() == expected_len,
+            "dfa cache file has the wrong length for {} states",
+            num_states
+        );
+
+        Ok(DenseDfaView { bytes, num_states, initial_state })
+    }
+
+    fn transition(&self, state: u32, byte: u8) -> u32 {
+        let offset = DENSE_DFA_HEADER_LEN + (state as usize * 256 + byte as usize) * 4;
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    pub fn distance(&self, state: u32) -> Distance {
+        let offset = DENSE_DFA_HEADER_LEN
+            + self.num_states as usize * 256 * 4
+            + state as usize * 2;
+        match self.bytes[offset] {
+            0 => Distance::Exact(self.bytes[offset + 1]),
+            _ => Distance::AtLeast(self.bytes[offset + 1]),
+        }
+    }
+}
+
+impl<'a> Automaton for DenseDfaView<'a> {
+    type State = u32;
+
+    #[inline]
+    fn start(&self) -> u32 {
+        self.initial_state
+    }
+
+    #[inline]
+    fn is_match(&self, state: &u32) -> bool {
+        matches!(self.distance(*state), Distance::Exact(_))
+    }
+
+    #[inline]
+    fn can_match(&self, state: &u32) -> bool {
+        // Mirrors `levenshtein_automata::DFA`'s own `Automaton` impl: state 0
+        // is guaranteed to be the sink state.
+        *state != 0
+    }
+
+    #[inline]
+    fn accept(&self, state: &u32, byte: u8) -> u32 {
+        self.transition(*state, byte)
+    }
+}
+
+/// Returns the number of bytes the UTF-8 scalar value starting with `byte`
+/// occupies, or `0` if `byte` can't start a code point (e.g. it's itself a
+/// continuation byte).
+#[inline]
+fn utf8_char_width(byte: u8) -> usize {
+    if byte & 0x80 == 0x00 {
+        1
+    } else if byte & 0xE0 == 0xC0 {
+        2
+    } else if byte & 0xF0 == 0xE0 {
+        3
+    } else if byte & 0xF8 == 0xF0 {
+        4
+    } else {
+        0
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SkipPrefixState {
+    /// Still skipping leading characters: `chars_skipped` full scalars have
+    /// been consumed so far, and `remaining_in_char` continuation bytes are
+    /// still expected before the current scalar is complete.
+    Skipping { chars_skipped: usize, remaining_in_char: usize },
+    /// Done skipping, now exact-matching the tail string byte by byte.
+    Matching { tail_pos: usize },
+}
+
 #[derive(Clone, Debug)]
-pub struct AnyFirstByteStr<'a> {
+pub struct SkipPrefixStr<'a> {
+    free_prefix_chars: usize,
     string: &'a [u8],
 }
 
-impl<'a> AnyFirstByteStr<'a> {
-    /// Constructs automaton that matches any first char followed by the given exact string.
+impl<'a> SkipPrefixStr<'a> {
+    /// Constructs an automaton that matches any `free_prefix_chars` leading
+    /// UTF-8 scalar values followed by the given exact string.
     #[inline]
-    pub fn new(string: &'a str) -> AnyFirstByteStr<'a> {
-        AnyFirstByteStr { string: string.as_bytes() }
+    pub fn new(free_prefix_chars: usize, string: &'a str) -> SkipPrefixStr<'a> {
+        SkipPrefixStr { free_prefix_chars, string: string.as_bytes() }
+    }
+
+    /// Moves on from having just consumed one more byte of the scalar being
+    /// skipped: either stays within it (`remaining_in_char` left) or, once
+    /// complete, starts the next scalar or switches to exact-matching the
+    /// tail once `free_prefix_chars` scalars have been skipped.
+    #[inline]
+    fn advance_skip(&self, chars_skipped: usize, remaining_in_char: usize) -> Option<SkipPrefixState> {
+        if remaining_in_char > 0 {
+            return Some(SkipPrefixState::Skipping { chars_skipped, remaining_in_char });
+        }
+
+        let chars_skipped = chars_skipped + 1;
+        if chars_skipped == self.free_prefix_chars {
+            Some(SkipPrefixState::Matching { tail_pos: 0 })
+        } else {
+            Some(SkipPrefixState::Skipping { chars_skipped, remaining_in_char: 0 })
+        }
     }
 }
 
-impl<'a> Automaton for AnyFirstByteStr<'a> {
-    type State = Option<usize>;
+impl<'a> Automaton for SkipPrefixStr<'a> {
+    type State = Option<SkipPrefixState>;
 
     #[inline]
-    fn start(&self) -> Option<usize> {
-        Some(0)
+    fn start(&self) -> Option<SkipPrefixState> {
+        if self.free_prefix_chars == 0 {
+            Some(SkipPrefixState::Matching { tail_pos: 0 })
+        } else {
+            Some(SkipPrefixState::Skipping { chars_skipped: 0, remaining_in_char: 0 })
+        }
     }
 
     #[inline]
-    fn is_match(&self, pos: &Option<usize>) -> bool {
-        // As we ignore the first char we must not forget
-        // that the original string to match is length + 1
-        *pos == Some(self.string.len() + 1)
+    fn is_match(&self, state: &Option<SkipPrefixState>) -> bool {
+        matches!(state, Some(SkipPrefixState::Matching { tail_pos }) if *tail_pos == self.string.len())
     }
 
     #[inline]
-    fn can_match(&self, pos: &Option<usize>) -> bool {
-        pos.is_some()
+    fn can_match(&self, state: &Option<SkipPrefixState>) -> bool {
+        state.is_some()
     }
 
     #[inline]
-    fn accept(&self, pos: &Option<usize>, byte: u8) -> Option<usize> {
-        // if we aren't already past the end...
-        if let Some(pos) = *pos {
-            // and we are checking for the first byte, that's always true...
-            if pos == 0 {
-                return Some(1);
+    fn accept(&self, state: &Option<SkipPrefixState>, byte: u8) -> Option<SkipPrefixState> {
+        match *state {
+            Some(SkipPrefixState::Skipping { chars_skipped, remaining_in_char: 0 }) => {
+                // `byte` is the leading byte of a new scalar; its high bits
+                // tell us how many continuation bytes to expect.
+                let width = utf8_char_width(byte);
+                if width == 0 {
+                    return None;
+                }
+                self.advance_skip(chars_skipped, width - 1)
             }
-
-            // or if there is still a matching byte at the current position + 1...
-            if self.string.get(pos - 1).cloned() == Some(byte) {
-                // then move forward
-                return Some(pos + 1);
+            Some(SkipPrefixState::Skipping { chars_skipped, remaining_in_char }) => {
+                // A continuation byte of the scalar we're currently skipping.
+                self.advance_skip(chars_skipped, remaining_in_char - 1)
             }
+            Some(SkipPrefixState::Matching { tail_pos }) => {
+                if self.string.get(tail_pos).cloned() == Some(byte) {
+                    Some(SkipPrefixState::Matching { tail_pos: tail_pos + 1 })
+                } else {
+                    None
+                }
+            }
+            None => None,
         }
-        // otherwise we're either past the end or didn't match the byte
-        None
     }
 }
+